@@ -0,0 +1,63 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backend-agnostic conversion of any `KeyValueDB` into a sled-backed `Database`.
+
+use crate::{other_io_err, Database};
+use kvdb::{DBTransaction, KeyValueDB};
+use std::io;
+
+/// How many keys to batch into a single `DBTransaction` before committing, to
+/// bound memory use while converting large databases.
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+impl Database {
+	/// Bulk-copy columns `0..num_columns` of `src` into the same columns of `self`,
+	/// committing a `DBTransaction` every `DEFAULT_BATCH_SIZE` keys; the default
+	/// (`None`) column of `src` is not copied. `progress` is called after each
+	/// committed batch with the column index and the number of keys copied so far
+	/// for that column, so long migrations can be monitored; a column with no keys
+	/// gets no call.
+	pub fn copy_from(&self, src: &dyn KeyValueDB, num_columns: u8, mut progress: impl FnMut(u32, usize)) -> io::Result<()> {
+		let real_columns = self.real_column_count();
+		if num_columns != real_columns {
+			return Err(other_io_err(format!(
+				"column count mismatch: asked to copy {} columns, destination has {}",
+				num_columns,
+				real_columns,
+			)));
+		}
+
+		for col in 0..num_columns as u32 {
+			let mut tr = DBTransaction::new();
+			let mut copied = 0;
+			for (key, value) in src.iter(Some(col)) {
+				tr.put_vec(Some(col), key.as_ref(), value.into_vec());
+				copied += 1;
+				if tr.ops.len() >= DEFAULT_BATCH_SIZE {
+					self.write(std::mem::replace(&mut tr, DBTransaction::new()))?;
+					progress(col, copied);
+				}
+			}
+			if !tr.ops.is_empty() {
+				self.write(tr)?;
+				progress(col, copied);
+			}
+		}
+
+		self.flush()
+	}
+}
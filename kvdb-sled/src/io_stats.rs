@@ -0,0 +1,131 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Operational I/O and size metrics for a sled-backed `Database`, so it can be
+//! monitored the same way kvdb-rocksdb is.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{other_io_err, Database};
+
+/// Selects whether `Database::io_stats` reports counters accumulated since the
+/// database was opened, or only those accumulated since the previous
+/// `IoStatsKind::SincePrevious` call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoStatsKind {
+	/// Cumulative counters since the database was opened.
+	Overall,
+	/// Counters accumulated since the previous `io_stats` call with this kind (or
+	/// since open, for the first such call). Calling with this kind resets the
+	/// baseline for the next such call; `Overall` calls leave it unchanged.
+	SincePrevious,
+}
+
+/// A snapshot of `Database`'s I/O activity and per-column sizes.
+#[derive(Debug, Clone, Default)]
+pub struct IoStats {
+	pub reads: u64,
+	pub writes: u64,
+	pub deletes: u64,
+	pub transactions: u64,
+	pub iterations: u64,
+	// sled doesn't expose a page cache hit counter, so this is always `0.0` for now.
+	pub cache_hit_ratio: f64,
+	/// Number of keys in each column, via `sled::Tree::len`.
+	pub column_sizes: Vec<u64>,
+	/// The cache budget, in bytes, `open` assigned to each column.
+	pub column_memory_budget: Vec<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct IoStatsCounters {
+	reads: AtomicU64,
+	writes: AtomicU64,
+	deletes: AtomicU64,
+	transactions: AtomicU64,
+	iterations: AtomicU64,
+	previous: Mutex<(u64, u64, u64, u64, u64)>,
+}
+
+impl IoStatsCounters {
+	pub(crate) fn tick_read(&self) {
+		self.reads.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn tick_write(&self, count: u64) {
+		self.writes.fetch_add(count, Ordering::Relaxed);
+	}
+
+	pub(crate) fn tick_delete(&self, count: u64) {
+		self.deletes.fetch_add(count, Ordering::Relaxed);
+	}
+
+	pub(crate) fn tick_transaction(&self) {
+		self.transactions.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn tick_iteration(&self) {
+		self.iterations.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+impl Database {
+	/// Report I/O counters and per-column key counts. With `IoStatsKind::Overall`
+	/// the counters are cumulative since `open`; with `IoStatsKind::SincePrevious`
+	/// they cover only the time since the previous `IoStatsKind::SincePrevious` call.
+	pub fn io_stats(&self, kind: IoStatsKind) -> IoStats {
+		let counters = &self.io_stats_counters;
+		let reads = counters.reads.load(Ordering::Relaxed);
+		let writes = counters.writes.load(Ordering::Relaxed);
+		let deletes = counters.deletes.load(Ordering::Relaxed);
+		let transactions = counters.transactions.load(Ordering::Relaxed);
+		let iterations = counters.iterations.load(Ordering::Relaxed);
+
+		let (reads, writes, deletes, transactions, iterations) = match kind {
+			IoStatsKind::Overall => (reads, writes, deletes, transactions, iterations),
+			IoStatsKind::SincePrevious => {
+				let mut previous = counters.previous.lock().expect("lock is not poisoned");
+				let delta = (
+					reads - previous.0,
+					writes - previous.1,
+					deletes - previous.2,
+					transactions - previous.3,
+					iterations - previous.4,
+				);
+				*previous = (reads, writes, deletes, transactions, iterations);
+				delta
+			}
+		};
+
+		IoStats {
+			reads,
+			writes,
+			deletes,
+			transactions,
+			iterations,
+			cache_hit_ratio: 0.0,
+			column_sizes: self.columns.iter().map(|tree| tree.len() as u64).collect(),
+			column_memory_budget: self.column_memory_budget.clone(),
+		}
+	}
+
+	/// Approximate on-disk footprint of the whole database, in bytes.
+	pub fn mem_stats(&self) -> io::Result<u64> {
+		self.db.size_on_disk().map_err(other_io_err)
+	}
+}
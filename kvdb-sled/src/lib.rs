@@ -17,10 +17,17 @@
 //! KeyValueDB implementation for sled database.
 
 use kvdb::{KeyValueDB, DBTransaction, DBValue, DBOp};
+use std::collections::{HashMap, HashSet};
 use std::io;
 use sled::Transactional as _;
 use log::warn;
 
+mod convert;
+mod io_stats;
+
+pub use io_stats::{IoStats, IoStatsKind};
+use io_stats::IoStatsCounters;
+
 const KB: u64 = 1024;
 const MB: u64 = 1024 * KB;
 const DB_DEFAULT_MEMORY_BUDGET_MB: u64 = 128;
@@ -30,56 +37,221 @@ fn other_io_err<E>(e: E) -> io::Error where E: Into<Box<dyn std::error::Error +
 }
 
 pub struct Database {
+	// the sled database backing `columns`; kept around so `backup`/`restore`
+	// can drive sled's whole-database `export`/`import`.
+	db: sled::Db,
 	// FIXME: sled currently support transactions only on tuples of trees,
 	// see https://github.com/spacejam/sled/issues/382#issuecomment-526548082
 	// `sled::Tree` corresponds to a `Column` in the KeyValueDB terminology.
 	columns: Vec<sled::Tree>,
 	path: String,
 	num_columns: u8,
+	io_stats_counters: IoStatsCounters,
+	// the per-column cache budget `open` computed from `DatabaseConfig::memory_budget`,
+	// indexed the same way as `columns`; recorded for `io_stats` reporting since sled
+	// itself only exposes one cache capacity for the whole database.
+	column_memory_budget: Vec<u64>,
+	no_default_column: bool,
 }
 
 // TODO: docs
 pub struct DatabaseConfig {
 	pub columns: Option<u8>,
 	pub memory_budget_mb: Option<u64>,
+	/// Per-column memory/cache budget, in bytes. Columns without an entry here
+	/// fall back to `memory_budget_mb` (or the crate default). `None` addresses
+	/// the default column.
+	pub memory_budget: HashMap<Option<u32>, u64>,
 	pub path: String,
+	/// Open the database in column-family-only mode: columns are addressed by a
+	/// required `u32` with no implicit default column and no off-by-one shift,
+	/// and exactly `columns` trees named `col{N}` are opened. If the database
+	/// was previously opened with the default column (`col{N+1}` naming), the
+	/// old trees are migrated down to `col{N}` on the first open in this mode;
+	/// that open fails instead if the old default column still holds data.
+	pub no_default_column: bool,
 }
 
 impl DatabaseConfig {
 	pub fn memory_budget(&self) -> u64 {
 		self.memory_budget_mb.unwrap_or(DB_DEFAULT_MEMORY_BUDGET_MB) * MB
 	}
+
+	/// Memory budget, in bytes, for `col`, falling back to the scalar
+	/// `memory_budget_mb` (or the crate default) when `col` has no entry in
+	/// `memory_budget`.
+	pub fn memory_budget_for(&self, col: Option<u32>) -> u64 {
+		self.memory_budget.get(&col).copied().unwrap_or_else(|| self.memory_budget())
+	}
 }
 
 impl Database {
 	pub fn open(config: DatabaseConfig) -> sled::Result<Database> {
+		let no_default_column = config.no_default_column;
+		// In column-family-only mode there's no implicit default column, so tree
+		// index `i` addresses real column `i` directly; otherwise tree 0 is the
+		// default column and tree index `i` (i >= 1) addresses real column `i - 1`.
+		// `tree_indices` and `column_of` capture that shift once for the rest of `open`.
+		let num_columns = if no_default_column {
+			config.columns.unwrap_or(0)
+		} else {
+			config.columns.unwrap_or(0) + 1
+		};
+		let tree_indices: Vec<u8> = (0..num_columns).collect();
+		let column_of = |i: u8| -> Option<u32> {
+			if no_default_column {
+				Some(i as u32)
+			} else if i == 0 {
+				None
+			} else {
+				Some(i as u32 - 1)
+			}
+		};
+
+		let column_memory_budget: Vec<u64> = tree_indices.iter().map(|&i| config.memory_budget_for(column_of(i))).collect();
+		let total_memory_budget: u64 = column_memory_budget.iter().sum();
+
 		let conf = sled::Config::default()
 			.path(&config.path)
-			.cache_capacity(config.memory_budget() / 2)
+			.cache_capacity(total_memory_budget / 2)
 			.flush_every_ms(Some(2_000)); // TODO: a random constant
 			// .snapshot_after_ops(100_000);
 
 		let db = conf.open()?;
-		let num_columns = config.columns.map_or(0, |c| c + 1);
-		let columns = (0..=num_columns)
-			.map(|i| db.open_tree(format!("col{}", i).as_bytes()))
+
+		// Key in sled's default tree recording that the migration below has run.
+		const MIGRATED_MARKER: &[u8] = b"kvdb-sled-no-default-column-migrated";
+		if no_default_column && db.get(MIGRATED_MARKER)?.is_none() {
+			// One-time migration: databases created before this mode existed used
+			// `col{N+1}` naming (tree 0 being the implicit default column, always
+			// present even if never written to). Copy each surviving old tree down
+			// to the new `col{N}` name so existing databases stay readable, then
+			// set `MIGRATED_MARKER` so later opens skip this step.
+			//
+			// The implicit default column (old tree `col0`) has no destination in
+			// this mode at all - if it genuinely holds data there's nowhere safe to
+			// put it, so refuse the migration outright rather than stranding it or
+			// letting it collide with real column 0's data.
+			//
+			// With the default column empty, `col{i}`'s only other possible
+			// occupant is real column `i - 1`'s data under the old naming, and
+			// that's guaranteed gone by the time we reach index `i`: we migrate in
+			// ascending order, so the previous iteration already moved it out of
+			// `col{i}` (its own `old_name`) and dropped it before this iteration
+			// writes into `col{i}` as `new_name`. So a non-empty destination here
+			// means our invariants are broken somehow - bail instead of silently
+			// skipping, which is what let wrong-column data cascade upward before.
+			let existing: HashSet<Vec<u8>> = db.tree_names().into_iter().map(|n| n.to_vec()).collect();
+			let default_column_name = b"col0".to_vec();
+			if existing.contains(&default_column_name) && !db.open_tree(&default_column_name)?.is_empty() {
+				return Err(sled::Error::Unsupported(
+					"cannot migrate to no_default_column mode: the database's default column still has data; move it into a named column first".into(),
+				));
+			}
+			for &i in &tree_indices {
+				let old_name = format!("col{}", i + 1).into_bytes();
+				if !existing.contains(&old_name) {
+					continue;
+				}
+				let new_name = format!("col{}", i).into_bytes();
+				let old_tree = db.open_tree(&old_name)?;
+				let new_tree = db.open_tree(&new_name)?;
+				if !new_tree.is_empty() {
+					return Err(sled::Error::ReportableBug(format!(
+						"no_default_column migration invariant violated: destination {:?} is non-empty before migrating {:?} into it",
+						new_name, old_name,
+					)));
+				}
+				for kv in old_tree.iter() {
+					let (k, v) = kv?;
+					new_tree.insert(k, v)?;
+				}
+				db.drop_tree(&old_name)?;
+			}
+			db.insert(MIGRATED_MARKER, &[][..])?;
+		}
+
+		let columns = tree_indices.iter()
+			.map(|&i| db.open_tree(format!("col{}", i).as_bytes()))
 			.collect::<sled::Result<Vec<_>>>()?;
 
 		Ok(Database {
+			db,
 			columns,
 			path: config.path,
 			num_columns,
+			io_stats_counters: IoStatsCounters::default(),
+			column_memory_budget,
+			no_default_column,
 		})
 	}
 
-	fn to_sled_column(col: Option<u32>) -> u8 {
-		col.map_or(0, |c| (c + 1) as u8)
+	/// Back up the database to a fresh sled database rooted at `target_path`, by
+	/// exporting every column's contents and importing it into the copy. Operators
+	/// can use this to take a consistent offline snapshot without stopping writers.
+	/// Safe to call against a `target_path` that already holds a previous backup.
+	pub fn backup(&self, target_path: &str) -> io::Result<()> {
+		let target = sled::Config::default().path(target_path).open().map_err(other_io_err)?;
+		clear_and_import(&target, self.db.export());
+		target.flush().map_err(other_io_err)?;
+		Ok(())
+	}
+
+	// Number of real, named columns this database was opened with, i.e. excluding
+	// the implicit default column that `columns` carries when `no_default_column`
+	// is false. This is what bounds-checking and error messages should be phrased
+	// against, not `columns.len()`, which is one larger than this whenever the
+	// default column is in play.
+	fn real_column_count(&self) -> u8 {
+		if self.no_default_column {
+			self.num_columns
+		} else {
+			self.num_columns.saturating_sub(1)
+		}
+	}
+
+	fn to_sled_column(&self, col: Option<u32>) -> io::Result<u8> {
+		let real_columns = self.real_column_count();
+		match col {
+			None if self.no_default_column => {
+				Err(other_io_err("this database has no default column; an explicit column is required"))
+			},
+			None => Ok(0),
+			Some(c) => {
+				if c >= real_columns as u32 {
+					return Err(other_io_err(format!("column index {} out of range ({} columns)", c, real_columns)));
+				}
+				Ok(if self.no_default_column { c as u8 } else { c as u8 + 1 })
+			},
+		}
+	}
+
+	/// Like `KeyValueDB::get`, but for column-family-only databases
+	/// (`DatabaseConfig::no_default_column`): `col` is required and the value is
+	/// a plain `Vec<u8>` rather than `DBValue`.
+	pub fn get_v2(&self, col: u32, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+		self.io_stats_counters.tick_read();
+		let col = self.to_sled_column(Some(col))?;
+		self.columns[col as usize].get(key).map(|maybe| maybe.map(|ivec| ivec.to_vec())).map_err(other_io_err)
+	}
+
+	/// Like `KeyValueDB::iter`, but for column-family-only databases
+	/// (`DatabaseConfig::no_default_column`): `col` is required and items are
+	/// plain `Vec<u8>` pairs rather than `Box<[u8]>`.
+	pub fn iter_v2<'a>(&'a self, col: u32) -> Box<dyn Iterator<Item=(Vec<u8>, Vec<u8>)> + 'a> {
+		self.io_stats_counters.tick_iteration();
+		let col = match self.to_sled_column(Some(col)) {
+			Ok(col) => col,
+			Err(_) => return Box::new(std::iter::empty()),
+		};
+		Box::new(self.columns[col as usize].iter().filter_map(|r| r.ok()).map(|(k, v)| (k.to_vec(), v.to_vec())))
 	}
 }
 
 impl KeyValueDB for Database {
 	fn get(&self, col: Option<u32>, key: &[u8]) -> io::Result<Option<DBValue>> {
-		let col = Self::to_sled_column(col);
+		self.io_stats_counters.tick_read();
+		let col = self.to_sled_column(col)?;
 		self.columns[col as usize]
 			.get(key)
 			.map(|maybe| maybe.map(|ivec| DBValue::from_slice(ivec.as_ref())))
@@ -114,45 +286,70 @@ impl KeyValueDB for Database {
 	}
 
 	fn write(&self, tr: DBTransaction) -> io::Result<()> {
-		// TODO: implement for more sizes via macro
-		let result = match &self.columns[..] {
-			[c1] => c1.transaction(|c1| {
+		let (inserts, deletes) = tr.ops.iter().fold((0u64, 0u64), |(ins, del), op| match op {
+			DBOp::Insert { .. } => (ins + 1, del),
+			DBOp::Delete { .. } => (ins, del + 1),
+		});
+
+		// Fast path: most callers only ever touch a single column, so avoid
+		// going through sled's generic slice `Transactional` impl for that case.
+		if let [c1] = &self.columns[..] {
+			let result = c1.transaction(|c1| {
 				let columns = [c1];
 				for op in &tr.ops {
 					match op {
 						DBOp::Insert { col, key, value } => {
-							let col = Self::to_sled_column(*col);
+							let col = self.to_sled_column(*col).map_err(sled::transaction::ConflictableTransactionError::Abort)?;
 							columns[col as usize].insert(key.as_ref(), value.as_ref())?;
 						},
 						DBOp::Delete { col, key } => {
-							let col = Self::to_sled_column(*col);
+							let col = self.to_sled_column(*col).map_err(sled::transaction::ConflictableTransactionError::Abort)?;
 							columns[col as usize].remove(key.as_ref())?;
 						}
 					}
 				}
 				Ok(())
-			}),
-			[c1, c2, c3, c4, c5, c6, c7, c8, c9] => {
-				(c1, c2, c3, c4, c5, c6, c7, c8, c9).transaction(|(c1, c2, c3, c4, c5, c6, c7, c8, c9)| {
-					let columns = [c1, c2, c3, c4, c5, c6, c7, c8, c9];
-					for op in &tr.ops {
-						match op {
-							DBOp::Insert { col, key, value } => {
-								let col = Self::to_sled_column(*col);
-								columns[col as usize].insert(key.as_ref(), value.as_ref())?;
-							},
-							DBOp::Delete { col, key } => {
-								let col = Self::to_sled_column(*col);
-								columns[col as usize].remove(key.as_ref())?;
-							}
-						}
+			});
+			let result = result.map_err(|e| match e {
+				sled::transaction::TransactionError::Abort(e) => e,
+				e => other_io_err(e),
+			});
+			if result.is_ok() {
+				self.io_stats_counters.tick_transaction();
+				self.io_stats_counters.tick_write(inserts);
+				self.io_stats_counters.tick_delete(deletes);
+			}
+			return result;
+		}
+
+		// General path: sled implements `Transactional` for `&[&TransactionalTree]`,
+		// so a slice of all the columns drives the whole transaction regardless of
+		// how many column families the database has.
+		let result = self.columns[..].transaction(|trees| {
+			for op in &tr.ops {
+				match op {
+					DBOp::Insert { col, key, value } => {
+						let col = self.to_sled_column(*col).map_err(sled::transaction::ConflictableTransactionError::Abort)?;
+						trees[col as usize].insert(key.as_ref(), value.as_ref())?;
+					},
+					DBOp::Delete { col, key } => {
+						let col = self.to_sled_column(*col).map_err(sled::transaction::ConflictableTransactionError::Abort)?;
+						trees[col as usize].remove(key.as_ref())?;
 					}
-					Ok(())
-				})
-			},
-			_ => panic!("only up to 9 columns are supported ATM"),
-		};
-		result.map_err(|_| other_io_err("transaction has failed"))
+				}
+			}
+			Ok(())
+		});
+		let result = result.map_err(|e| match e {
+			sled::transaction::TransactionError::Abort(e) => e,
+			e => other_io_err(e),
+		});
+		if result.is_ok() {
+			self.io_stats_counters.tick_transaction();
+			self.io_stats_counters.tick_write(inserts);
+			self.io_stats_counters.tick_delete(deletes);
+		}
+		result
 	}
 
 	fn flush(&self) -> io::Result<()> {
@@ -163,7 +360,11 @@ impl KeyValueDB for Database {
 	}
 
 	fn iter<'a>(&'a self, col: Option<u32>) -> Box<dyn Iterator<Item=(Box<[u8]>, Box<[u8]>)> + 'a> {
-		let col = Self::to_sled_column(col);
+		self.io_stats_counters.tick_iteration();
+		let col = match self.to_sled_column(col) {
+			Ok(col) => col,
+			Err(_) => return Box::new(std::iter::empty()),
+		};
 		let iter = DatabaseIter {
 			inner: self.columns[col as usize].iter(),
 		};
@@ -173,7 +374,11 @@ impl KeyValueDB for Database {
 	fn iter_from_prefix<'a>(&'a self, col: Option<u32>, prefix: &'a [u8])
 		-> Box<dyn Iterator<Item=(Box<[u8]>, Box<[u8]>)> + 'a>
 	{
-		let col = Self::to_sled_column(col);
+		self.io_stats_counters.tick_iteration();
+		let col = match self.to_sled_column(col) {
+			Ok(col) => col,
+			Err(_) => return Box::new(std::iter::empty()),
+		};
 		let iter = DatabaseIter {
 			inner: self.columns[col as usize].scan_prefix(prefix),
 		};
@@ -181,10 +386,28 @@ impl KeyValueDB for Database {
 	}
 
 	fn restore(&self, new_db: &str) -> io::Result<()> {
-		unimplemented!("TODO")
+		// `export`/`import` preserve tree names (`col{N}`), so importing a
+		// snapshot taken by `backup` reconstructs the same set of columns.
+		let new_db = sled::Config::default().path(new_db).open().map_err(other_io_err)?;
+		clear_and_import(&self.db, new_db.export());
+		self.flush()
 	}
 }
 
+// `sled::Db::import` panics as soon as an imported key already exists in the
+// destination, so restoring onto a live, already-populated database (the
+// realistic recovery case) or backing up twice into the same `target_path`
+// would otherwise crash instead of replacing the old contents. Clear every
+// existing tree in `dst` first so the import always lands on an empty slate.
+fn clear_and_import<I: Iterator<Item = Vec<Vec<u8>>>>(dst: &sled::Db, export: Vec<(Vec<u8>, Vec<u8>, I)>) {
+	for name in dst.tree_names() {
+		if let Ok(tree) = dst.open_tree(&name) {
+			let _ = tree.clear();
+		}
+	}
+	dst.import(export);
+}
+
 struct DatabaseIter {
 	inner: sled::Iter,
 }
@@ -209,8 +432,94 @@ impl Drop for Database {
 
 #[cfg(test)]
 mod tests {
+	use super::{Database, DatabaseConfig};
+	use kvdb::{DBTransaction, KeyValueDB};
+	use std::collections::HashMap;
+
 	#[test]
 	fn it_works() {
 		assert_eq!(2 + 2, 4);
 	}
+
+	fn temp_db_path(name: &str) -> String {
+		let path = std::env::temp_dir().join(format!("kvdb-sled-test-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&path);
+		path.to_str().unwrap().to_owned()
+	}
+
+	fn config(path: String, columns: Option<u8>, no_default_column: bool) -> DatabaseConfig {
+		DatabaseConfig {
+			columns,
+			memory_budget_mb: None,
+			memory_budget: HashMap::new(),
+			path,
+			no_default_column,
+		}
+	}
+
+	#[test]
+	fn no_default_column_migration_preserves_existing_data() {
+		let path = temp_db_path("migration");
+
+		{
+			let old = Database::open(config(path.clone(), Some(2), false)).unwrap();
+			let mut tr = DBTransaction::new();
+			tr.put(Some(0), b"k0", b"v0");
+			tr.put(Some(1), b"k1", b"v1");
+			old.write(tr).unwrap();
+		}
+
+		let migrated = Database::open(config(path, Some(2), true)).unwrap();
+		assert_eq!(migrated.get_v2(0, b"k0").unwrap(), Some(b"v0".to_vec()));
+		assert_eq!(migrated.get_v2(1, b"k1").unwrap(), Some(b"v1".to_vec()));
+	}
+
+	#[test]
+	fn copy_from_with_no_real_columns_does_not_panic() {
+		// `columns: None` means 0 real/named columns (just the default column),
+		// which used to underflow `self.num_columns - 1` inside `copy_from`.
+		let src = Database::open(config(temp_db_path("copy-src"), None, false)).unwrap();
+		let dst = Database::open(config(temp_db_path("copy-dst"), None, false)).unwrap();
+		dst.copy_from(&src as &dyn KeyValueDB, 0, |_, _| {}).unwrap();
+	}
+
+	#[test]
+	fn no_default_column_migration_refuses_when_default_column_has_data() {
+		let path = temp_db_path("migration-default-column");
+
+		{
+			let old = Database::open(config(path.clone(), Some(2), false)).unwrap();
+			let mut tr = DBTransaction::new();
+			tr.put(None, b"dk", b"dv");
+			tr.put(Some(0), b"k0", b"v0");
+			tr.put(Some(1), b"k1", b"v1");
+			old.write(tr).unwrap();
+		}
+
+		// The default column genuinely holds data, so there's no safe `col{N}`
+		// to migrate it to - this must error rather than silently stranding or
+		// cross-wiring real columns 0 and 1, as it used to.
+		assert!(Database::open(config(path, Some(2), true)).is_err());
+	}
+
+	#[test]
+	fn restore_replaces_existing_data_without_panicking() {
+		let live = Database::open(config(temp_db_path("restore-live"), Some(1), false)).unwrap();
+		let mut tr = DBTransaction::new();
+		tr.put(Some(0), b"k", b"live-value");
+		live.write(tr).unwrap();
+
+		let snapshot_path = temp_db_path("restore-snapshot");
+		{
+			let snapshot = Database::open(config(snapshot_path.clone(), Some(1), false)).unwrap();
+			let mut tr = DBTransaction::new();
+			tr.put(Some(0), b"k", b"snapshot-value");
+			snapshot.write(tr).unwrap();
+		}
+
+		// `sled::Db::import` panics on any key collision, so restoring onto this
+		// already-populated `live` database used to crash instead of replacing it.
+		live.restore(&snapshot_path).unwrap();
+		assert_eq!(live.get(Some(0), b"k").unwrap().unwrap(), &b"snapshot-value"[..]);
+	}
 }
\ No newline at end of file